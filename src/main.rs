@@ -1,34 +1,206 @@
 use serde::{Deserialize, Serialize};
-use std::io::{Read, Write};
-use std::net::{Shutdown, TcpListener, TcpStream};
-use std::sync::mpsc;
-use std::sync::{Arc, RwLock};
-use std::thread;
+use std::path::PathBuf;
+use std::sync::mpsc as std_mpsc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, UnixListener};
+// `opcua_client`'s `Client::connect_to_endpoint`/`Session::run_async` hand back the
+// session wrapped in this same tokio `RwLock`, which is what makes `blocking_write()`
+// (used by `with_session_blocking`) available on the `Arc<RwLock<Session>>` they return.
+use tokio::sync::{mpsc, OwnedSemaphorePermit, RwLock, Semaphore};
 
 use opcua_client::prelude::*;
 
-const LINE_FEED: u8 = 0x0A;
+const PROTOCOL_VERSION: u32 = 1;
+const SUPPORTED_FEATURES: &[&str] = &[
+    "read",
+    "write",
+    "browse",
+    "history_read_raw",
+    "subscribe",
+    "secure_endpoints",
+    "unix_socket",
+];
+
+/// A connection that has sent nothing for this long has its session stopped and its
+/// socket shut down, so a stalled client doesn't leak a task and a server session.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Upper bound on the number of connections served at once, enforced by a semaphore
+/// so a flood of clients can't make the daemon spin up unbounded OPC-UA sessions.
+const DEFAULT_MAX_CONNECTIONS: usize = 256;
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 enum Request {
+    Hello {
+        seq: u64,
+        protocol_version: u32,
+    },
+    Ping {
+        seq: u64,
+    },
     Connect {
+        seq: u64,
         host: String,
         port: u16,
         namespace: u16,
         endpoint: Option<String>,
+        /// e.g. `"Basic256Sha256"`, `"Aes128Sha256RsaOaep"`. Defaults to `"None"`.
+        security_policy: Option<String>,
+        /// `"None"`, `"Sign"`, or `"SignAndEncrypt"`. Defaults to `"None"`.
+        security_mode: Option<String>,
+        /// Defaults to `Identity::Anonymous`.
+        identity: Option<Identity>,
+        /// Whether to trust the server's certificate on first connect rather than
+        /// requiring it to already be in the client's trusted certificate store.
+        /// Defaults to `false`.
+        trust_server_certificate: Option<bool>,
+        /// When set, the connection is refused unless the server's certificate
+        /// thumbprint matches this pinned value.
+        server_certificate_thumbprint: Option<String>,
     },
     Read {
+        seq: u64,
         node_ids: Vec<String>,
     },
+    Subscribe {
+        seq: u64,
+        node_ids: Vec<String>,
+        publishing_interval_ms: f64,
+        sampling_interval_ms: f64,
+    },
+    Unsubscribe {
+        seq: u64,
+        subscription_id: u32,
+    },
+    Write {
+        seq: u64,
+        writes: Vec<WriteItem>,
+    },
+    Browse {
+        seq: u64,
+        node_id: String,
+        max_references: u32,
+    },
+    HistoryReadRaw {
+        seq: u64,
+        node_id: String,
+        /// RFC 3339 timestamp marking the start of the range to read.
+        start_time: String,
+        /// RFC 3339 timestamp marking the end of the range to read.
+        end_time: String,
+        max_values: u32,
+    },
+}
+
+/// One node/value pair from a `Request::Write`. `data_type` names the OPC-UA built-in
+/// type (`"Boolean"`, `"Int32"`, `"Double"`, `"String"`, ...) that `value` is converted
+/// into before being sent to the server.
+#[derive(Debug, Serialize, Deserialize)]
+struct WriteItem {
+    node_id: String,
+    value: serde_json::Value,
+    data_type: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Identity {
+    Anonymous,
+    UserName {
+        username: String,
+        password: String,
+    },
+    X509 {
+        certificate_path: String,
+        private_key_path: String,
+    },
+}
+
+impl Request {
+    fn seq(&self) -> u64 {
+        match self {
+            Request::Hello { seq, .. } => *seq,
+            Request::Ping { seq, .. } => *seq,
+            Request::Connect { seq, .. } => *seq,
+            Request::Read { seq, .. } => *seq,
+            Request::Subscribe { seq, .. } => *seq,
+            Request::Unsubscribe { seq, .. } => *seq,
+            Request::Write { seq, .. } => *seq,
+            Request::Browse { seq, .. } => *seq,
+            Request::HistoryReadRaw { seq, .. } => *seq,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 enum Response {
+    Hi {
+        protocol_version: u32,
+        supported_features: Vec<String>,
+    },
+    Pong,
     ConnectOk,
-    Error { message: String },
-    ReadOk { values: Vec<DataValue> },
+    Error {
+        message: String,
+    },
+    ReadOk {
+        values: Vec<DataValue>,
+    },
+    SubscribeOk {
+        subscription_id: u32,
+    },
+    UnsubscribeOk,
+    WriteOk {
+        results: Vec<StatusCode>,
+    },
+    BrowseOk {
+        references: Vec<BrowseReference>,
+    },
+    HistoryReadRawOk {
+        values: Vec<DataValue>,
+    },
+}
+
+/// One child reference returned by a `Request::Browse`.
+#[derive(Debug, Serialize, Deserialize)]
+struct BrowseReference {
+    node_id: String,
+    browse_name: String,
+    node_class: String,
+}
+
+/// Messages the daemon pushes to a client without being asked, as opposed to a
+/// `Response` which always answers a specific `Request`.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Event {
+    DataChange {
+        subscription_id: u32,
+        node_id: String,
+        value: DataValue,
+    },
+}
+
+/// The envelope every frame is sent in. `category` lets a client that has several
+/// requests in flight route a `Response` back to the caller that sent the matching
+/// `request_seq`, while handing every `Event` to a separate subscription listener.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "category", rename_all = "snake_case")]
+enum Message {
+    Response {
+        request_seq: u64,
+        #[serde(flatten)]
+        response: Response,
+    },
+    Event {
+        seq: u64,
+        #[serde(flatten)]
+        event: Event,
+    },
 }
 
 struct Server {
@@ -38,92 +210,200 @@ struct Server {
     endpoint: Option<String>,
 }
 
+/// Forwards data-change notifications from an opcua_client subscription callback into
+/// the connection's outgoing message channel, so they can be written to the socket by
+/// the single writer task that owns the write half.
+///
+/// `subscription_id` is filled in once `create_subscription` returns its id, since the
+/// callback has to be handed to the client before the id is known. `event_seq` is the
+/// connection-wide event counter shared by every subscription on the connection.
+struct DataChangeForwarder {
+    subscription_id: Arc<Mutex<u32>>,
+    event_seq: Arc<Mutex<u64>>,
+    message_sender: mpsc::UnboundedSender<Message>,
+}
+
+impl OnSubscriptionNotification for DataChangeForwarder {
+    fn on_data_change(&mut self, data_change_items: &[&MonitoredItem]) {
+        let subscription_id = *self.subscription_id.lock().unwrap();
+        for item in data_change_items {
+            let mut event_seq = self.event_seq.lock().unwrap();
+            *event_seq += 1;
+            let message = Message::Event {
+                seq: *event_seq,
+                event: Event::DataChange {
+                    subscription_id,
+                    node_id: item.item_to_monitor().node_id.to_string(),
+                    value: item.last_value().clone(),
+                },
+            };
+            drop(event_seq);
+            if self.message_sender.send(message).is_err() {
+                // Writer task is gone (client disconnected); nothing more to do.
+                break;
+            }
+        }
+    }
+}
+
 struct State {
     server: Option<Server>,
     session: Option<Arc<RwLock<Session>>>,
-    command_sender: Option<mpsc::Sender<SessionCommand>>,
+    command_sender: Option<std_mpsc::Sender<SessionCommand>>,
+    message_sender: mpsc::UnboundedSender<Message>,
+    event_seq: Arc<Mutex<u64>>,
+    subscription_ids: Vec<u32>,
+    /// Set once a matching `Request::Hello` has been accepted; no other request is
+    /// processed before this.
+    hello_done: bool,
 }
 
-fn main() {
+#[tokio::main]
+async fn main() {
     opcua_console_logging::init();
 
-    const DEFAULT_BIND_ADDRESS: &str = "127.0.0.1";
-    const PORT: u16 = 8341;
+    let max_connections = std::env::var("MAX_CONNECTIONS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(DEFAULT_MAX_CONNECTIONS);
+    let connection_limit = Arc::new(Semaphore::new(max_connections));
 
-    let bind_address = match std::env::var("BIND_ADDRESS") {
-        Ok(address) => address,
-        Err(_) => DEFAULT_BIND_ADDRESS.to_string(),
-    };
+    match std::env::var("LISTEN_SOCKET") {
+        Ok(path) => {
+            // Remove a stale socket file left behind by a previous run so bind doesn't
+            // fail with "address in use".
+            let _ = std::fs::remove_file(&path);
+            println!("Listening on {}", path);
+            let listener = UnixListener::bind(&path).unwrap();
 
-    println!("Listening on {}:{}", bind_address, PORT);
+            loop {
+                match listener.accept().await {
+                    Ok((stream, addr)) => {
+                        let peer_label = format!("{:?}", addr);
+                        println!("New connection from {}", peer_label);
+                        let permit = connection_limit.clone().acquire_owned().await.unwrap();
+                        tokio::spawn(handle_connection(stream, peer_label, permit));
+                    }
+                    Err(e) => eprintln!("Error: {}", e),
+                }
+            }
+        }
+        Err(_) => {
+            const DEFAULT_BIND_ADDRESS: &str = "127.0.0.1";
+            const PORT: u16 = 8341;
 
-    let listener = TcpListener::bind(format!("{}:{}", bind_address, PORT)).unwrap();
+            let bind_address = match std::env::var("BIND_ADDRESS") {
+                Ok(address) => address,
+                Err(_) => DEFAULT_BIND_ADDRESS.to_string(),
+            };
 
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                println!("New connection from {}", stream.peer_addr().unwrap());
-                thread::spawn(move || {
-                    handle_client(stream);
-                });
-            }
+            println!("Listening on {}:{}", bind_address, PORT);
+
+            let listener = TcpListener::bind(format!("{}:{}", bind_address, PORT))
+                .await
+                .unwrap();
 
-            Err(e) => {
-                eprintln!("Error: {}", e);
+            loop {
+                match listener.accept().await {
+                    Ok((stream, addr)) => {
+                        let peer_label = addr.to_string();
+                        println!("New connection from {}", peer_label);
+                        let permit = connection_limit.clone().acquire_owned().await.unwrap();
+                        tokio::spawn(handle_connection(stream, peer_label, permit));
+                    }
+                    Err(e) => eprintln!("Error: {}", e),
+                }
             }
         }
     }
 }
 
-fn handle_client(mut stream: TcpStream) {
+/// Drives a single client connection end to end: frames newline-delimited JSON off the
+/// read half, dispatches each `Request` through `handle_request`, and relies on a
+/// dedicated writer task (fed by `message_sender`) to serialize replies and subscription
+/// events onto the write half. Holding `_permit` for the lifetime of the task is what
+/// keeps the server at or below `MAX_CONNECTIONS`.
+async fn handle_connection<S>(stream: S, peer_label: String, _permit: OwnedSemaphorePermit)
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let (read_half, mut write_half) = tokio::io::split(stream);
+    let mut lines = BufReader::new(read_half).lines();
+
+    let (message_sender, mut message_receiver) = mpsc::unbounded_channel::<Message>();
+
+    let writer_task = tokio::spawn(async move {
+        while let Some(message) = message_receiver.recv().await {
+            let data = serde_json::to_string(&message).unwrap() + "\n";
+            if let Err(e) = write_half.write_all(data.as_bytes()).await {
+                eprintln!("Error while writing message to socket: {}", e);
+                break;
+            }
+        }
+    });
+
     let mut state = State {
         session: None,
         server: None,
         command_sender: None,
+        message_sender,
+        event_seq: Arc::new(Mutex::new(0)),
+        subscription_ids: Vec::new(),
+        hello_done: false,
     };
-    let mut buf = [0 as u8; 512];
-    let mut raw_request: Vec<u8> = Vec::with_capacity(512);
 
     loop {
-        match stream.read(&mut buf) {
-            Ok(0) => {
-                break;
-            }
-            Ok(size) => {
-                let data = &buf[0..size];
-
-                if let Some(index) = data.iter().position(|&byte| byte == LINE_FEED) {
-                    let (request_end, rest) = data.split_at(index);
-                    raw_request.extend_from_slice(request_end);
-
-                    match parse_request(raw_request) {
-                        Ok(request) => {
-                            state = match handle_request(state, request) {
-                                (Ok(response), new_state) => {
-                                    handle_response(&stream, response);
-                                    new_state
-                                }
-                                (Err(error), new_state) => {
-                                    handle_error(&stream, error);
-                                    new_state
-                                }
-                            }
+        match tokio::time::timeout(IDLE_TIMEOUT, lines.next_line()).await {
+            Ok(Ok(Some(line))) => match parse_request(line) {
+                Ok(request) => {
+                    let request_seq = request.seq();
+                    state = match handle_request(state, request).await {
+                        (Ok(response), new_state) => {
+                            send_response(&new_state, request_seq, response);
+                            new_state
                         }
-                        Err(err) => {
-                            eprintln!("Could not parse Request {}", err);
+                        (Err(error), new_state) => {
+                            send_response(
+                                &new_state,
+                                request_seq,
+                                Response::Error { message: error },
+                            );
+                            new_state
                         }
                     }
-
-                    raw_request = Vec::from(rest);
-                } else {
-                    raw_request.extend_from_slice(data);
                 }
-            }
-            Err(e) => {
-                stream.shutdown(Shutdown::Both).unwrap();
+                Err(err) => {
+                    eprintln!("Could not parse Request: {}", err);
+                }
+            },
+            Ok(Ok(None)) => break,
+            Ok(Err(e)) => {
                 eprintln!("Error while reading from socket: {}", e);
                 break;
             }
+            Err(_) => {
+                eprintln!(
+                    "Client {} idle for too long, closing connection",
+                    peer_label
+                );
+                break;
+            }
+        }
+    }
+
+    if let Some(session) = state.session.clone() {
+        let subscription_ids = state.subscription_ids.clone();
+        let result = with_session_blocking(session, move |session| {
+            for subscription_id in &subscription_ids {
+                if let Err(err) = session.delete_subscription(*subscription_id) {
+                    eprintln!("Error deleting subscription {}: {}", subscription_id, err);
+                }
+            }
+            Ok(())
+        })
+        .await;
+        if let Err(err) = result {
+            eprintln!("Error during subscription cleanup: {}", err);
         }
     }
 
@@ -131,50 +411,148 @@ fn handle_client(mut stream: TcpStream) {
         sender.send(SessionCommand::Stop).unwrap();
     }
 
-    println!("Client loop finished for {}", stream.peer_addr().unwrap());
+    drop(state.message_sender);
+    let _ = writer_task.await;
+
+    println!("Client loop finished for {}", peer_label);
 }
 
-fn parse_request(raw_request: Vec<u8>) -> Result<Request, String> {
-    if let Ok(req) = String::from_utf8(raw_request) {
-        if let Ok(request) = serde_json::from_str::<Request>(&req) {
-            Ok(request)
-        } else {
-            Err(String::from("request is not valid"))
-        }
-    } else {
-        Err(String::from("request is not valid utf-8"))
+fn send_response(state: &State, request_seq: u64, response: Response) {
+    let message = Message::Response {
+        request_seq,
+        response,
+    };
+    if state.message_sender.send(message).is_err() {
+        eprintln!(
+            "Writer task gone, dropping response for request {}",
+            request_seq
+        );
     }
 }
 
-fn handle_request(state: State, req: Request) -> (Result<Response, String>, State) {
+fn parse_request(line: String) -> Result<Request, String> {
+    serde_json::from_str::<Request>(&line).map_err(|err| format!("request is not valid: {}", err))
+}
+
+/// Runs a blocking OPC-UA `Session` call on a dedicated blocking thread via
+/// `spawn_blocking`, so a slow round-trip to the server doesn't stall a tokio worker
+/// thread (and with it every other connection's I/O and the accept loop).
+async fn with_session_blocking<F, T>(session: Arc<RwLock<Session>>, f: F) -> Result<T, String>
+where
+    F: FnOnce(&mut Session) -> Result<T, String> + Send + 'static,
+    T: Send + 'static,
+{
+    match tokio::task::spawn_blocking(move || {
+        let mut session = session.blocking_write();
+        f(&mut session)
+    })
+    .await
+    {
+        Ok(result) => result,
+        Err(err) => Err(format!("OPC-UA worker task panicked: {}", err)),
+    }
+}
+
+async fn handle_request(state: State, req: Request) -> (Result<Response, String>, State) {
+    if !state.hello_done {
+        return match req {
+            Request::Hello {
+                protocol_version, ..
+            } => match protocol_version {
+                v if v == PROTOCOL_VERSION => {
+                    let mut state = state;
+                    state.hello_done = true;
+                    (
+                        Ok(Response::Hi {
+                            protocol_version: PROTOCOL_VERSION,
+                            supported_features: SUPPORTED_FEATURES
+                                .iter()
+                                .map(|feature| feature.to_string())
+                                .collect(),
+                        }),
+                        state,
+                    )
+                }
+                v => (
+                    Err(format!(
+                        "Unsupported protocol version {} (server supports {})",
+                        v, PROTOCOL_VERSION
+                    )),
+                    state,
+                ),
+            },
+            _ => (
+                Err(String::from(
+                    "Hello handshake required before any other request",
+                )),
+                state,
+            ),
+        };
+    }
+
     match req {
+        Request::Hello { .. } => (
+            Err(String::from("Hello handshake already completed")),
+            state,
+        ),
+        Request::Ping { .. } => (Ok(Response::Pong), state),
         Request::Connect {
             host,
             port,
             namespace,
             endpoint,
+            security_policy,
+            security_mode,
+            identity,
+            trust_server_certificate,
+            server_certificate_thumbprint,
         } => match state.session {
             None => {
-                let session = connect(&host, port, &endpoint);
-                let shared = session.clone();
-                let command_sender = Session::run_async(shared);
-                (
-                    Ok(Response::ConnectOk),
-                    State {
-                        command_sender: Some(command_sender),
-                        server: Some(Server {
-                            host,
-                            port,
-                            namespace,
-                            endpoint,
-                        }),
-                        session: Some(session),
-                    },
+                let security_policy = match parse_security_policy(security_policy.as_deref()) {
+                    Ok(policy) => policy,
+                    Err(err) => return (Err(err), state),
+                };
+                let security_mode = match parse_security_mode(security_mode.as_deref()) {
+                    Ok(mode) => mode,
+                    Err(err) => return (Err(err), state),
+                };
+
+                match connect(
+                    &host,
+                    port,
+                    &endpoint,
+                    security_policy,
+                    security_mode,
+                    &identity.unwrap_or(Identity::Anonymous),
+                    trust_server_certificate.unwrap_or(false),
+                    &server_certificate_thumbprint,
                 )
+                .await
+                {
+                    Ok(session) => {
+                        let shared = session.clone();
+                        let command_sender = Session::run_async(shared);
+                        (
+                            Ok(Response::ConnectOk),
+                            State {
+                                command_sender: Some(command_sender),
+                                server: Some(Server {
+                                    host,
+                                    port,
+                                    namespace,
+                                    endpoint,
+                                }),
+                                session: Some(session),
+                                ..state
+                            },
+                        )
+                    }
+                    Err(err) => (Err(err), state),
+                }
             }
             Some(_) => (Err(String::from("Session already in progress")), state),
         },
-        Request::Read { node_ids } => match state.session {
+        Request::Read { node_ids, .. } => match state.session {
             None => (Err(String::from("Cannot read, no active session")), state),
             Some(ref session) => {
                 let namespace = match state.server {
@@ -186,62 +564,590 @@ fn handle_request(state: State, req: Request) -> (Result<Response, String>, Stat
                     .map(|v| NodeId::new(namespace, v.clone()).into())
                     .collect();
                 let my_session = session.clone();
-                let mut the_session = my_session.write().unwrap();
+                let result = with_session_blocking(my_session, move |session| {
+                    session
+                        .read(&nodes)
+                        .map_err(|err| format!("Unable to read from OPCUA server: {}", err))
+                })
+                .await;
 
-                match the_session.read(&nodes) {
+                match result {
                     Ok(values) => (Ok(Response::ReadOk { values }), state),
-                    Err(err) => (
-                        Err(format!("Unable to read from OPCUA server: {}", err)),
-                        state,
-                    ),
+                    Err(err) => (Err(err), state),
+                }
+            }
+        },
+        Request::Subscribe {
+            node_ids,
+            publishing_interval_ms,
+            sampling_interval_ms,
+            ..
+        } => match state.session {
+            None => (
+                Err(String::from("Cannot subscribe, no active session")),
+                state,
+            ),
+            Some(ref session) => {
+                let namespace = match state.server {
+                    Some(ref server) => server.namespace,
+                    None => 0,
+                };
+                let my_session = session.clone();
+                let subscription_id_cell = Arc::new(Mutex::new(0u32));
+                let forwarder = DataChangeForwarder {
+                    subscription_id: subscription_id_cell.clone(),
+                    event_seq: state.event_seq.clone(),
+                    message_sender: state.message_sender.clone(),
+                };
+                // Tracks whether `create_subscription` has already succeeded server-side, so a
+                // later failure in this closure (e.g. `create_monitored_items`) doesn't leave the
+                // subscription untracked and leaked past connection teardown (see cleanup below).
+                let created_subscription_id: Arc<Mutex<Option<u32>>> = Arc::new(Mutex::new(None));
+                let created_subscription_id_inner = created_subscription_id.clone();
+
+                let result = with_session_blocking(my_session, move |session| {
+                    let subscription_id = session
+                        .create_subscription(publishing_interval_ms, 10, 30, 0, 0, true, forwarder)
+                        .map_err(|err| format!("Unable to create subscription: {}", err))?;
+                    *subscription_id_cell.lock().unwrap() = subscription_id;
+                    *created_subscription_id_inner.lock().unwrap() = Some(subscription_id);
+
+                    let items_to_create: Vec<MonitoredItemCreateRequest> = node_ids
+                        .iter()
+                        .map(|v| {
+                            let node_id: NodeId = NodeId::new(namespace, v.clone());
+                            MonitoredItemCreateRequest::new(
+                                node_id.into(),
+                                MonitoringMode::Reporting,
+                                MonitoringParameters {
+                                    sampling_interval: sampling_interval_ms,
+                                    ..Default::default()
+                                },
+                            )
+                        })
+                        .collect();
+
+                    session
+                        .create_monitored_items(
+                            subscription_id,
+                            TimestampsToReturn::Both,
+                            &items_to_create,
+                        )
+                        .map_err(|err| format!("Unable to create monitored items: {}", err))?;
+
+                    Ok(subscription_id)
+                })
+                .await;
+
+                match result {
+                    Ok(subscription_id) => {
+                        let mut state = state;
+                        state.subscription_ids.push(subscription_id);
+                        (Ok(Response::SubscribeOk { subscription_id }), state)
+                    }
+                    Err(err) => {
+                        let mut state = state;
+                        if let Some(subscription_id) = *created_subscription_id.lock().unwrap() {
+                            state.subscription_ids.push(subscription_id);
+                        }
+                        (Err(err), state)
+                    }
+                }
+            }
+        },
+        Request::Unsubscribe {
+            subscription_id, ..
+        } => match state.session {
+            None => (
+                Err(String::from("Cannot unsubscribe, no active session")),
+                state,
+            ),
+            Some(ref session) => {
+                let my_session = session.clone();
+                let result = with_session_blocking(my_session, move |session| {
+                    session
+                        .delete_subscription(subscription_id)
+                        .map_err(|err| format!("Unable to delete subscription: {}", err))
+                })
+                .await;
+
+                match result {
+                    Ok(_) => {
+                        let mut state = state;
+                        state.subscription_ids.retain(|id| *id != subscription_id);
+                        (Ok(Response::UnsubscribeOk), state)
+                    }
+                    Err(err) => (Err(err), state),
+                }
+            }
+        },
+        Request::Write { writes, .. } => match state.session {
+            None => (Err(String::from("Cannot write, no active session")), state),
+            Some(ref session) => {
+                let namespace = match state.server {
+                    Some(ref server) => server.namespace,
+                    None => 0,
+                };
+
+                let mut write_values = Vec::with_capacity(writes.len());
+                for item in &writes {
+                    let variant = match json_to_variant(&item.value, &item.data_type) {
+                        Ok(variant) => variant,
+                        Err(err) => return (Err(err), state),
+                    };
+                    write_values.push(WriteValue {
+                        node_id: NodeId::new(namespace, item.node_id.clone()),
+                        attribute_id: AttributeId::Value as u32,
+                        index_range: UAString::null(),
+                        value: DataValue::value_only(variant),
+                    });
+                }
+
+                let my_session = session.clone();
+                let result = with_session_blocking(my_session, move |session| {
+                    session
+                        .write(&write_values)
+                        .map_err(|err| format!("Unable to write to OPCUA server: {}", err))
+                })
+                .await;
+
+                match result {
+                    Ok(results) => (Ok(Response::WriteOk { results }), state),
+                    Err(err) => (Err(err), state),
                 }
             }
         },
+        Request::Browse {
+            node_id,
+            max_references,
+            ..
+        } => match state.session {
+            None => (Err(String::from("Cannot browse, no active session")), state),
+            Some(ref session) => {
+                let namespace = match state.server {
+                    Some(ref server) => server.namespace,
+                    None => 0,
+                };
+
+                let browse_description = BrowseDescription {
+                    node_id: NodeId::new(namespace, node_id),
+                    browse_direction: BrowseDirection::Forward,
+                    reference_type_id: ReferenceTypeId::HierarchicalReferences.into(),
+                    include_subtypes: true,
+                    node_class_mask: 0,
+                    result_mask: BrowseDescriptionResultMask::All as u32,
+                };
+
+                let my_session = session.clone();
+                let result = with_session_blocking(my_session, move |session| {
+                    session
+                        .browse(&[browse_description])
+                        .map_err(|err| format!("Unable to browse OPCUA server: {}", err))
+                })
+                .await;
+
+                match result {
+                    Ok(results) => {
+                        let references = results
+                            .into_iter()
+                            .flat_map(|result| result.references.unwrap_or_default())
+                            .take(max_references as usize)
+                            .map(|reference| BrowseReference {
+                                node_id: reference.node_id.node_id.to_string(),
+                                browse_name: reference.browse_name.name.to_string(),
+                                node_class: format!("{:?}", reference.node_class),
+                            })
+                            .collect();
+                        (Ok(Response::BrowseOk { references }), state)
+                    }
+                    Err(err) => (Err(err), state),
+                }
+            }
+        },
+        Request::HistoryReadRaw {
+            node_id,
+            start_time,
+            end_time,
+            max_values,
+            ..
+        } => match state.session {
+            None => (
+                Err(String::from("Cannot read history, no active session")),
+                state,
+            ),
+            Some(ref session) => {
+                let namespace = match state.server {
+                    Some(ref server) => server.namespace,
+                    None => 0,
+                };
+
+                let start_time = match parse_timestamp(&start_time) {
+                    Ok(time) => time,
+                    Err(err) => return (Err(err), state),
+                };
+                let end_time = match parse_timestamp(&end_time) {
+                    Ok(time) => time,
+                    Err(err) => return (Err(err), state),
+                };
+
+                let history_read_details = ReadRawModifiedDetails {
+                    is_read_modified: false,
+                    start_time,
+                    end_time,
+                    num_values_per_node: max_values,
+                    return_bounds: false,
+                };
+                let history_read_value_id = HistoryReadValueId {
+                    node_id: NodeId::new(namespace, node_id),
+                    index_range: UAString::null(),
+                    data_encoding: QualifiedName::null(),
+                    continuation_point: ByteString::null(),
+                };
+
+                let my_session = session.clone();
+                let result = with_session_blocking(my_session, move |session| {
+                    session
+                        .history_read_raw_modified(
+                            history_read_details,
+                            TimestampsToReturn::Both,
+                            false,
+                            &[history_read_value_id],
+                        )
+                        .map_err(|err| format!("Unable to read history from OPCUA server: {}", err))
+                })
+                .await;
+
+                match result {
+                    Ok(results) => {
+                        let values = results
+                            .into_iter()
+                            .flat_map(|result| result.history_data)
+                            .flat_map(|data| data.data_values.unwrap_or_default())
+                            .collect();
+                        (Ok(Response::HistoryReadRawOk { values }), state)
+                    }
+                    Err(err) => (Err(err), state),
+                }
+            }
+        },
+    }
+}
+
+/// Converts a JSON value into the `Variant` for the OPC-UA built-in type named by
+/// `data_type`, so a `Request::Write` can carry plain JSON over the wire. Every numeric
+/// conversion is range-checked rather than truncating or wrapping out-of-range input, so
+/// a bad value is rejected instead of silently being written as something else.
+fn json_to_variant(value: &serde_json::Value, data_type: &str) -> Result<Variant, String> {
+    let invalid = || format!("Value {} is not valid for data type {}", value, data_type);
+    match data_type {
+        "Boolean" => value.as_bool().map(Variant::from).ok_or_else(invalid),
+        "SByte" => value
+            .as_i64()
+            .and_then(|v| i8::try_from(v).ok())
+            .map(Variant::from)
+            .ok_or_else(invalid),
+        "Byte" => value
+            .as_u64()
+            .and_then(|v| u8::try_from(v).ok())
+            .map(Variant::from)
+            .ok_or_else(invalid),
+        "Int16" => value
+            .as_i64()
+            .and_then(|v| i16::try_from(v).ok())
+            .map(Variant::from)
+            .ok_or_else(invalid),
+        "UInt16" => value
+            .as_u64()
+            .and_then(|v| u16::try_from(v).ok())
+            .map(Variant::from)
+            .ok_or_else(invalid),
+        "Int32" => value
+            .as_i64()
+            .and_then(|v| i32::try_from(v).ok())
+            .map(Variant::from)
+            .ok_or_else(invalid),
+        "UInt32" => value
+            .as_u64()
+            .and_then(|v| u32::try_from(v).ok())
+            .map(Variant::from)
+            .ok_or_else(invalid),
+        "Int64" => value.as_i64().map(Variant::from).ok_or_else(invalid),
+        "UInt64" => value.as_u64().map(Variant::from).ok_or_else(invalid),
+        "Float" => value
+            .as_f64()
+            .and_then(|v| {
+                let narrowed = v as f32;
+                narrowed.is_finite().then_some(narrowed)
+            })
+            .map(Variant::from)
+            .ok_or_else(invalid),
+        "Double" => value.as_f64().map(Variant::from).ok_or_else(invalid),
+        "String" => value.as_str().map(Variant::from).ok_or_else(invalid),
+        other => Err(format!("Unknown data type: {}", other)),
     }
 }
 
-fn connect(host: &str, port: u16, endpoint: &Option<String>) -> Arc<RwLock<Session>> {
-    let endpoint = if let Some(value) = endpoint {
+/// Parses an RFC 3339 timestamp as used by `Request::HistoryReadRaw` into the OPC-UA
+/// `DateTime` type expected by `ReadRawModifiedDetails`.
+fn parse_timestamp(value: &str) -> Result<DateTime, String> {
+    value
+        .parse::<DateTime>()
+        .map_err(|_| format!("Invalid timestamp: {}", value))
+}
+
+fn parse_security_policy(security_policy: Option<&str>) -> Result<SecurityPolicy, String> {
+    match security_policy.unwrap_or("None") {
+        "None" => Ok(SecurityPolicy::None),
+        "Basic128Rsa15" => Ok(SecurityPolicy::Basic128Rsa15),
+        "Basic256" => Ok(SecurityPolicy::Basic256),
+        "Basic256Sha256" => Ok(SecurityPolicy::Basic256Sha256),
+        "Aes128Sha256RsaOaep" => Ok(SecurityPolicy::Aes128Sha256RsaOaep),
+        "Aes256Sha256RsaPss" => Ok(SecurityPolicy::Aes256Sha256RsaPss),
+        other => Err(format!("Unknown security policy: {}", other)),
+    }
+}
+
+fn parse_security_mode(security_mode: Option<&str>) -> Result<MessageSecurityMode, String> {
+    match security_mode.unwrap_or("None") {
+        "None" => Ok(MessageSecurityMode::None),
+        "Sign" => Ok(MessageSecurityMode::Sign),
+        "SignAndEncrypt" => Ok(MessageSecurityMode::SignAndEncrypt),
+        other => Err(format!("Unknown security mode: {}", other)),
+    }
+}
+
+/// The endpoint's advertised user-token policy that `identity`'s token must match:
+/// `ActivateSession` matches the identity token sent by the client against the
+/// endpoint's declared `user_identity_tokens` by policy id/token type, so this must stay
+/// in sync with `identity_token`.
+fn user_token_policy(identity: &Identity) -> UserTokenPolicy {
+    match identity {
+        Identity::Anonymous => UserTokenPolicy::anonymous(),
+        Identity::UserName { .. } => UserTokenPolicy::user_name(),
+        Identity::X509 { .. } => UserTokenPolicy::x509(),
+    }
+}
+
+fn identity_token(identity: &Identity) -> IdentityToken {
+    match identity {
+        Identity::Anonymous => IdentityToken::Anonymous,
+        Identity::UserName { username, password } => {
+            IdentityToken::UserName(username.clone(), password.clone())
+        }
+        Identity::X509 {
+            certificate_path,
+            private_key_path,
+        } => IdentityToken::X509(
+            PathBuf::from(certificate_path),
+            PathBuf::from(private_key_path),
+        ),
+    }
+}
+
+async fn connect(
+    host: &str,
+    port: u16,
+    endpoint: &Option<String>,
+    security_policy: SecurityPolicy,
+    security_mode: MessageSecurityMode,
+    identity: &Identity,
+    trust_server_certificate: bool,
+    server_certificate_thumbprint: &Option<String>,
+) -> Result<Arc<RwLock<Session>>, String> {
+    let endpoint_path = if let Some(value) = endpoint {
         value
     } else {
         ""
     };
 
-    let url = format!("opc.tcp://{}:{}{}", host, port, endpoint);
-
-    let mut client = ClientBuilder::new()
-        .application_name("Simple Client")
-        .application_uri("urn:SimpleClient")
-        .session_retry_limit(3)
-        .trust_server_certs(true)
-        .create_sample_keypair(true)
-        .single_threaded_executor()
-        .client()
-        .unwrap();
-
-    client
-        .connect_to_endpoint(
-            (
-                url.as_ref(),
-                SecurityPolicy::None.to_str(),
-                MessageSecurityMode::None,
-                UserTokenPolicy::anonymous(),
-            ),
-            IdentityToken::Anonymous,
-        )
-        .unwrap()
-}
+    let url = format!("opc.tcp://{}:{}{}", host, port, endpoint_path);
+    let identity_token = identity_token(identity);
+    let user_token_policy = user_token_policy(identity);
+
+    // Building the client (keypair creation) and connecting to the endpoint (TCP connect
+    // plus the secure-channel handshake, retried up to `session_retry_limit` times) are
+    // blocking calls, so they're run via `spawn_blocking` just like every other `Session`
+    // call in this file (see `with_session_blocking`) rather than stalling a tokio worker.
+    let session = match tokio::task::spawn_blocking(move || {
+        let mut client = ClientBuilder::new()
+            .application_name("Simple Client")
+            .application_uri("urn:SimpleClient")
+            .session_retry_limit(3)
+            .trust_server_certs(trust_server_certificate)
+            .create_sample_keypair(true)
+            .single_threaded_executor()
+            .client()
+            .ok_or_else(|| String::from("Unable to build OPC-UA client"))?;
+
+        client
+            .connect_to_endpoint(
+                (
+                    url.as_ref(),
+                    security_policy.to_str(),
+                    security_mode,
+                    user_token_policy,
+                ),
+                identity_token,
+            )
+            .map_err(|status| format!("Unable to connect to {}: {}", url, status))
+    })
+    .await
+    {
+        Ok(result) => result?,
+        Err(err) => return Err(format!("OPC-UA worker task panicked: {}", err)),
+    };
+
+    if let Some(expected_thumbprint) = server_certificate_thumbprint {
+        let actual_thumbprint = session
+            .read()
+            .await
+            .session_info()
+            .server_certificate
+            .as_ref()
+            .map(|cert| cert.thumbprint().as_hex_string());
 
-fn handle_error(mut stream: &TcpStream, message: String) {
-    let response = Response::Error { message };
-    let data = serde_json::to_string(&response).unwrap() + "\n";
-    stream.write_all(&data.into_bytes()).unwrap();
+        if actual_thumbprint.as_deref() != Some(expected_thumbprint.as_str()) {
+            // Don't leave a live, authenticated session open to a server whose certificate
+            // didn't match the pinned thumbprint.
+            let disconnect_result = with_session_blocking(session.clone(), |session| {
+                session.disconnect();
+                Ok(())
+            })
+            .await;
+            if let Err(err) = disconnect_result {
+                eprintln!("Error disconnecting after thumbprint mismatch: {}", err);
+            }
+
+            return Err(format!(
+                "Server certificate thumbprint {:?} does not match pinned thumbprint {}",
+                actual_thumbprint, expected_thumbprint
+            ));
+        }
+    }
+
+    Ok(session)
 }
 
-fn handle_response(mut stream: &TcpStream, response: Response) {
-    let data = serde_json::to_string(&response).unwrap() + "\n";
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_request_accepts_a_valid_line() {
+        let request = parse_request(r#"{"type":"ping","seq":1}"#.to_string()).unwrap();
+        assert_eq!(request.seq(), 1);
+        assert!(matches!(request, Request::Ping { .. }));
+    }
+
+    #[test]
+    fn parse_request_rejects_garbage() {
+        assert!(parse_request("not json".to_string()).is_err());
+    }
+
+    #[test]
+    fn message_response_round_trips_through_json() {
+        let message = Message::Response {
+            request_seq: 7,
+            response: Response::Pong,
+        };
+        let json = serde_json::to_string(&message).unwrap();
+        let parsed: Message = serde_json::from_str(&json).unwrap();
+        match parsed {
+            Message::Response {
+                request_seq,
+                response: Response::Pong,
+            } => assert_eq!(request_seq, 7),
+            other => panic!("expected Message::Response(Pong), got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn message_event_round_trips_through_json() {
+        let message = Message::Event {
+            seq: 3,
+            event: Event::DataChange {
+                subscription_id: 1,
+                node_id: "ns=2;s=Tag1".to_string(),
+                value: DataValue::null(),
+            },
+        };
+        let json = serde_json::to_string(&message).unwrap();
+        let parsed: Message = serde_json::from_str(&json).unwrap();
+        match parsed {
+            Message::Event {
+                seq,
+                event:
+                    Event::DataChange {
+                        subscription_id,
+                        node_id,
+                        ..
+                    },
+            } => {
+                assert_eq!(seq, 3);
+                assert_eq!(subscription_id, 1);
+                assert_eq!(node_id, "ns=2;s=Tag1");
+            }
+            other => panic!("expected Message::Event(DataChange), got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn json_to_variant_accepts_in_range_values() {
+        assert!(json_to_variant(&serde_json::json!(true), "Boolean").is_ok());
+        assert!(json_to_variant(&serde_json::json!(100), "Byte").is_ok());
+        assert!(json_to_variant(&serde_json::json!(-100), "SByte").is_ok());
+        assert!(json_to_variant(&serde_json::json!(1000), "Int16").is_ok());
+        assert!(json_to_variant(&serde_json::json!(70000), "Int32").is_ok());
+        assert!(json_to_variant(&serde_json::json!(1.5), "Double").is_ok());
+        assert!(json_to_variant(&serde_json::json!(1.5), "Float").is_ok());
+        assert!(json_to_variant(&serde_json::json!("hello"), "String").is_ok());
+    }
+
+    #[test]
+    fn json_to_variant_rejects_out_of_range_values() {
+        assert!(json_to_variant(&serde_json::json!(300), "Byte").is_err());
+        assert!(json_to_variant(&serde_json::json!(-200), "SByte").is_err());
+        assert!(json_to_variant(&serde_json::json!(-1), "UInt16").is_err());
+        assert!(json_to_variant(&serde_json::json!(70000), "Int16").is_err());
+        assert!(json_to_variant(&serde_json::json!(1e300), "Float").is_err());
+    }
+
+    #[test]
+    fn json_to_variant_rejects_wrong_shape_and_unknown_type() {
+        assert!(json_to_variant(&serde_json::json!("not a bool"), "Boolean").is_err());
+        assert!(json_to_variant(&serde_json::json!(1), "NotARealType").is_err());
+    }
+
+    #[test]
+    fn parse_timestamp_accepts_rfc3339() {
+        assert!(parse_timestamp("2024-01-01T00:00:00Z").is_ok());
+    }
+
+    #[test]
+    fn parse_timestamp_rejects_garbage() {
+        assert!(parse_timestamp("not-a-timestamp").is_err());
+    }
+
+    #[test]
+    fn parse_security_policy_handles_known_and_unknown_values() {
+        assert!(matches!(parse_security_policy(None), Ok(SecurityPolicy::None)));
+        assert!(matches!(
+            parse_security_policy(Some("Basic256Sha256")),
+            Ok(SecurityPolicy::Basic256Sha256)
+        ));
+        assert!(parse_security_policy(Some("NotAPolicy")).is_err());
+    }
 
-    if let Err(e) = stream.write_all(&data.into_bytes()) {
-        eprintln!("Error while writing response to socket: {}", e);
+    #[test]
+    fn parse_security_mode_handles_known_and_unknown_values() {
+        assert!(matches!(
+            parse_security_mode(None),
+            Ok(MessageSecurityMode::None)
+        ));
+        assert!(matches!(
+            parse_security_mode(Some("SignAndEncrypt")),
+            Ok(MessageSecurityMode::SignAndEncrypt)
+        ));
+        assert!(parse_security_mode(Some("NotAMode")).is_err());
     }
 }